@@ -1,12 +1,18 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt::{self, Debug, Display};
-use chrono::{Utc,Local,DateTime};
-use mongodb::bson::{Bson, doc};
+use chrono::{TimeZone,Utc,Local,DateTime};
+use mongodb::bson::{Bson, doc, Document};
 use rust_decimal::prelude::*;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
+/// Every operation, across all tickers, lives in this single collection,
+/// distinguished by its `ticker` field.
+const OPERATIONS_COLLECTION: &str = "operations";
+
 #[derive(StructOpt)]
 #[structopt(
     global_settings = &[AppSettings::NoBinaryName]
@@ -34,11 +40,41 @@ enum Command {
         quantity: i64,
         price: Decimal,
         date: Option<DateTime<Local>>,
+        #[structopt(long, default_value = "average")]
+        cost_basis: CostBasis,
     },
 
     AvgPrice {
         filter: Option<String>,
         until: Option<DateTime<Local>>,
+        #[structopt(long, default_value = "average")]
+        cost_basis: CostBasis,
+    },
+
+    Gains {
+        filter: Option<String>,
+        until: Option<DateTime<Local>>,
+        #[structopt(long, default_value = "average")]
+        cost_basis: CostBasis,
+    },
+
+    Value {
+        filter: Option<String>,
+    },
+
+    Export {
+        filter: Option<String>,
+        #[structopt(long, default_value = "ledger")]
+        format: ExportFormat,
+        #[structopt(long, default_value = "average")]
+        cost_basis: CostBasis,
+    },
+
+    Candles {
+        ticker: String,
+        resolution: Resolution,
+        from: Option<DateTime<Local>>,
+        to: Option<DateTime<Local>>,
     }
 }
 
@@ -66,11 +102,239 @@ impl FromStr for OperationKind {
     }
 }
 
+/// Which buy lots a sell consumes first when computing cost basis.
+#[derive(Debug, Clone, Copy)]
+enum CostBasis {
+    Fifo,
+    Lifo,
+    Average,
+}
+
+impl Display for CostBasis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
+impl FromStr for CostBasis {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_lowercase();
+        match lower.as_str() {
+            "fifo" => Ok(CostBasis::Fifo),
+            "lifo" => Ok(CostBasis::Lifo),
+            "average" => Ok(CostBasis::Average),
+            _ => Err(String::from("Unknown cost basis mode"))
+        }
+    }
+}
+
+/// Output format for `Command::Export`.
+#[derive(Debug, Clone, Copy)]
+enum ExportFormat {
+    Ledger,
+    Csv,
+}
+
+impl Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_lowercase();
+        match lower.as_str() {
+            "ledger" => Ok(ExportFormat::Ledger),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(String::from("Unknown export format"))
+        }
+    }
+}
+
+/// Bucket width for `Command::Candles`.
+#[derive(Debug, Clone, Copy)]
+enum Resolution {
+    OneMinute,
+    FiveMinutes,
+    OneHour,
+    OneDay,
+}
+
+impl Resolution {
+    fn seconds(&self) -> i64 {
+        match self {
+            Resolution::OneMinute => 60,
+            Resolution::FiveMinutes => 5 * 60,
+            Resolution::OneHour => 60 * 60,
+            Resolution::OneDay => 24 * 60 * 60,
+        }
+    }
+}
+
+impl Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "1m" => Ok(Resolution::OneMinute),
+            "5m" => Ok(Resolution::FiveMinutes),
+            "1h" => Ok(Resolution::OneHour),
+            "1d" => Ok(Resolution::OneDay),
+            _ => Err(String::from("Unknown resolution, expected 1m, 5m, 1h or 1d"))
+        }
+    }
+}
+
+/// One OHLC+volume bucket of operations for `Command::Candles`.
+#[derive(Clone, Copy)]
+struct Candle {
+    open: Decimal,
+    high: Decimal,
+    low: Decimal,
+    close: Decimal,
+    volume: i64,
+}
+
+/// A single buy not yet fully sold off, tracked so a later sell can draw
+/// its cost basis from the right lot(s) depending on `CostBasis`.
+struct Lot {
+    date: DateTime<Local>,
+    quantity_remaining: i64,
+    unit_cost: Decimal,
+}
+
 struct Position {
     ticker: String,
-    value: f64,
+    value: Decimal,
     quantity: i64,
-    average_price: f64,
+    average_price: Decimal,
+    realized_gain: Decimal,
+    /// Acquisition date of the oldest lot still open, i.e. how far back the
+    /// remaining holding's cost basis reaches. `None` when fully closed out.
+    oldest_lot_date: Option<DateTime<Local>>,
+}
+
+/// Looks up a market price for a ticker as of a given date, so unrealized
+/// gains can be marked against something other than the cost basis itself.
+trait PriceOracle {
+    fn price_for(&self, ticker: &str, date: &DateTime<Local>) -> Option<Decimal>;
+}
+
+/// The simplest oracle available without a live feed: the price of the
+/// most recent operation recorded for the ticker at or before `date`.
+struct LastOperationPriceOracle<'a> {
+    db_client: &'a mongodb::sync::Client,
+}
+
+impl<'a> PriceOracle for LastOperationPriceOracle<'a> {
+    fn price_for(&self, ticker: &str, date: &DateTime<Local>) -> Option<Decimal> {
+        let collection = self.db_client.database("stonks").collection(OPERATIONS_COLLECTION);
+        let filter = doc! {
+            "$and": [
+                { "ticker": ticker },
+                { "date": { "$lte": Bson::DateTime(date.with_timezone(&Utc)) } }
+            ]
+        };
+        let options = mongodb::options::FindOptions::builder()
+            .sort(doc! { "date": -1 })
+            .limit(1)
+            .build();
+        let mut cursor = collection.find(filter, options).ok()?;
+        let document = cursor.next()?.ok()?;
+        Decimal::from_str(document.get_str("price").ok()?).ok()
+    }
+}
+
+/// Fetches a live market quote for a ticker, as opposed to
+/// `LastOperationPriceOracle` which only ever reflects prices already
+/// recorded in our own journal.
+trait PriceSource {
+    fn last_quote(&self, ticker: &str) -> Result<Decimal, String>;
+}
+
+/// Pulls the raw `"ap"` (ask price) number out of an Alpaca quote response
+/// body as a string slice and parses it with `Decimal::from_str`, without
+/// ever routing it through `serde_json`'s number parsing. `serde_json`
+/// tokenizes JSON numbers through `f64` before any `Deserialize` impl sees
+/// them unless its `arbitrary_precision` crate feature is enabled, which
+/// would silently reintroduce the same precision loss `Decimal` exists to
+/// avoid. Scanning the raw text sidesteps that entirely.
+fn parse_ask_price(body: &str) -> Result<Decimal, String> {
+    let key = "\"ap\":";
+    let start = body.find(key).ok_or_else(|| String::from("quote response missing \"ap\" field"))? + key.len();
+    let end = body[start..]
+        .find(|c: char| c == ',' || c == '}')
+        .map(|offset| start + offset)
+        .ok_or_else(|| String::from("quote response truncated after \"ap\" field"))?;
+    Decimal::from_str(body[start..end].trim()).map_err(|e| e.to_string())
+}
+
+/// Live quotes from Alpaca's market data API. Credentials come from the
+/// same env vars the official Alpaca CLI/SDKs use. Quotes are cached for
+/// the lifetime of the source, i.e. for the duration of one command.
+struct AlpacaPriceSource {
+    http_client: reqwest::Client,
+    runtime: tokio::runtime::Runtime,
+    base_url: String,
+    api_key_id: String,
+    api_secret_key: String,
+    quote_cache: RefCell<HashMap<String, Decimal>>,
+}
+
+impl AlpacaPriceSource {
+    pub fn new() -> Result<AlpacaPriceSource, String> {
+        let api_key_id = std::env::var("APCA_API_KEY_ID")
+            .map_err(|_| String::from("APCA_API_KEY_ID is not set"))?;
+        let api_secret_key = std::env::var("APCA_API_SECRET_KEY")
+            .map_err(|_| String::from("APCA_API_SECRET_KEY is not set"))?;
+        let base_url = std::env::var("APCA_API_DATA_URL")
+            .unwrap_or_else(|_| String::from("https://data.alpaca.markets"));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(AlpacaPriceSource {
+            http_client: reqwest::Client::new(),
+            runtime,
+            base_url,
+            api_key_id,
+            api_secret_key,
+            quote_cache: RefCell::new(HashMap::new()),
+        })
+    }
+
+    fn fetch_quote(&self, ticker: &str) -> Result<Decimal, String> {
+        let url = format!("{}/v2/stocks/{}/quotes/latest", self.base_url, ticker);
+        self.runtime.block_on(async {
+            let body = self.http_client.get(&url)
+                .header("APCA-API-KEY-ID", &self.api_key_id)
+                .header("APCA-API-SECRET-KEY", &self.api_secret_key)
+                .send().await
+                .map_err(|e| e.to_string())?
+                .text().await
+                .map_err(|e| e.to_string())?;
+            parse_ask_price(&body)
+        })
+    }
+}
+
+impl PriceSource for AlpacaPriceSource {
+    fn last_quote(&self, ticker: &str) -> Result<Decimal, String> {
+        if let Some(price) = self.quote_cache.borrow().get(ticker) {
+            return Ok(*price);
+        }
+        let price = self.fetch_quote(ticker)?;
+        self.quote_cache.borrow_mut().insert(ticker.to_string(), price);
+        Ok(price)
+    }
 }
 
 struct App {
@@ -82,12 +346,55 @@ impl App {
     pub fn new() -> App {
         let db_client = mongodb::sync::Client::with_uri_str("mongodb://127.0.0.1:27017/")
             .expect("Could not connect to mongodb");
+        App::migrate_legacy_collections(&db_client);
         App {
             editor: Editor::<()>::new(),
             db_client: db_client,
         }
     }
 
+    /// Older versions of this tool kept one collection per ticker with no `ticker`
+    /// field on the documents. Fold any such collections into `OPERATIONS_COLLECTION`,
+    /// stamping each migrated document with the ticker its collection was named after.
+    fn migrate_legacy_collections(db_client: &mongodb::sync::Client) {
+        let db = db_client.database("stonks");
+        let names = match db.list_collection_names(None) {
+            Ok(names) => names,
+            Err(_) => return,
+        };
+
+        let operations = db.collection(OPERATIONS_COLLECTION);
+        for name in names {
+            if name == OPERATIONS_COLLECTION {
+                continue;
+            }
+            let legacy: mongodb::sync::Collection<Document> = db.collection(name.as_str());
+            let cursor = match legacy.find(None, None) {
+                Ok(cursor) => cursor,
+                Err(_) => continue,
+            };
+            let mut migration_failed = false;
+            for document in cursor {
+                if let Ok(mut document) = document {
+                    document.insert("ticker", &name);
+                    // Legacy documents stored price as a BSON double; re-encode it as
+                    // the decimal string every read path now expects.
+                    if let Ok(price) = document.get_f64("price") {
+                        document.insert("price", Decimal::from_f64(price).unwrap_or(Decimal::ZERO).to_string());
+                    }
+                    if operations.insert_one(document, None).is_err() {
+                        migration_failed = true;
+                    }
+                }
+            }
+            if migration_failed {
+                println!("Failed to migrate some operations from legacy collection \"{}\"; leaving it in place", name);
+                continue;
+            }
+            let _ = legacy.drop(None);
+        }
+    }
+
     pub fn tokenize_line(&self, line: &str) -> Vec<String> {
         let mut parts = Vec::<String>::new();
         let mut current = String::new();
@@ -133,18 +440,28 @@ impl App {
     }
 
     pub fn cmd_list(&self, filter: &Option<String>) {
-        let db = self.db_client.database("stonks");
-        match db.list_collection_names(doc! {
-            "name": { "$regex": filter.as_ref().unwrap_or(&String::from("")) }
-        }) {
-            Ok(names) => {
-                for name in names {
-                    let collection = db.collection(name.as_str());
-                    println!("{} ({} operations)",
-                        name, collection.estimated_document_count(None).unwrap());
-                }
-            },
-            Err(_) => (),
+        let collection = self.db_client.database("stonks").collection(OPERATIONS_COLLECTION);
+
+        let mut pipeline = Vec::new();
+        if let Some(f) = filter {
+            pipeline.push(doc! {
+                "$match": { "ticker": { "$regex": &f } }
+            });
+        }
+        pipeline.push(doc! {
+            "$group": { "_id": "$ticker", "operations": { "$sum": 1 } }
+        });
+        let cursor = match collection.aggregate(pipeline, None) {
+            Ok(cursor) => cursor,
+            Err(e) => { println!("{}", e); return }
+        };
+
+        for document in cursor {
+            if let Ok(document) = document {
+                let ticker = document.get_str("_id").unwrap();
+                let count = document.get_i32("operations").unwrap_or(0);
+                println!("{} ({} operations)", ticker, count);
+            }
         }
         println!("ls filter: {:?}", filter);
     }
@@ -157,11 +474,14 @@ impl App {
             Some(date) => date,
             None => now,
         };
-        let collection = self.db_client.database("stonks").collection(ticker);
+        let collection = self.db_client.database("stonks").collection(OPERATIONS_COLLECTION);
         match collection.insert_one(doc! {
+            "ticker": ticker,
             "kind": kind.to_string(),
             "quantity": quantity,
-            "price": price.to_f64().unwrap(),
+            // Stored as its exact decimal string representation rather than
+            // an f64 so cost-basis math never accumulates rounding error.
+            "price": price.to_string(),
             "date": Bson::DateTime(actual_date.with_timezone(&Utc)),
         }, None) {
             Ok(_) => (),
@@ -174,12 +494,30 @@ impl App {
         self.add_operation(OperationKind::Buy, ticker, quantity, price, date);
     }
 
-    pub fn cmd_sell(&self, ticker: &String, quantity: &i64, price: &Decimal, date: &Option<DateTime<Local>>) {
+    pub fn cmd_sell(&self, ticker: &String, quantity: &i64, price: &Decimal, date: &Option<DateTime<Local>>, cost_basis: CostBasis) {
+        // Check against the position as of this sell's own date (it hasn't been
+        // inserted yet), so backdated sells are validated against what was actually
+        // held at that point in the history rather than the position today.
+        let position_before = match self.calculate_position(ticker, date, cost_basis) {
+            Ok(position) => position,
+            Err(e) => { println!("{}", e); return }
+        };
+        if *quantity > position_before.quantity {
+            println!("Sell quantity {} exceeds open position of {} for {}", quantity, position_before.quantity, ticker);
+            return;
+        }
+
         self.add_operation(OperationKind::Sell, ticker, quantity, price, date);
+        let position = match self.calculate_position(ticker, &None, cost_basis) {
+            Ok(position) => position,
+            Err(e) => { println!("{}", e); return }
+        };
+        println!("realized gain so far: {:.2}", position.realized_gain);
     }
 
-    pub fn cmd_avgprice(&self, filter: &Option<String>, until: &Option<DateTime<Local>>) {
-        let collection = self.db_client.database("stonks").collection("stocks");
+    /// Distinct tickers in the operations collection matching an optional regex filter.
+    fn matching_tickers(&self, filter: &Option<String>) -> Vec<String> {
+        let collection = self.db_client.database("stonks").collection(OPERATIONS_COLLECTION);
 
         let mut pipeline = Vec::new();
         if let Some(f) = filter {
@@ -193,7 +531,7 @@ impl App {
         );
         let cursor = match collection.aggregate(pipeline, None) {
             Ok(cursor) => cursor,
-            Err(e) => { println!("{}", e); return }
+            Err(e) => { println!("{}", e); return Vec::new() }
         };
 
         let mut tickers = Vec::new();
@@ -205,20 +543,108 @@ impl App {
                 Err(_) => ()
             }
         }
-        for ticker in tickers {
-            let average = self.average_price(&ticker, until);
-            println!("{}\t{:>9.2}", &ticker, average)
+        tickers
+    }
+
+    pub fn cmd_avgprice(&self, filter: &Option<String>, until: &Option<DateTime<Local>>, cost_basis: CostBasis) {
+        for ticker in self.matching_tickers(filter) {
+            match self.average_price(&ticker, until, cost_basis) {
+                Ok(average) => println!("{}\t{:>9.2}", &ticker, average),
+                Err(e) => println!("{}\t{}", &ticker, e),
+            }
+        }
+    }
+
+    pub fn cmd_gains(&self, filter: &Option<String>, until: &Option<DateTime<Local>>, cost_basis: CostBasis) {
+        let now = &chrono::Local::now();
+        let date = match until {
+            Some(date) => date,
+            None => now,
+        };
+        let oracle = LastOperationPriceOracle { db_client: &self.db_client };
+
+        for ticker in self.matching_tickers(filter) {
+            let position = match self.calculate_position(&ticker, until, cost_basis) {
+                Ok(position) => position,
+                Err(e) => { println!("{}\t{}", &ticker, e); continue }
+            };
+            let unrealized_gain = match oracle.price_for(&ticker, date) {
+                Some(market_price) => market_price * Decimal::from(position.quantity) - position.value,
+                None => Decimal::ZERO,
+            };
+            let held_since = match position.oldest_lot_date {
+                Some(date) => date.format("%Y-%m-%d").to_string(),
+                None => String::from("-"),
+            };
+            println!("{}\trealized {:>9.2}\tunrealized {:>9.2}\theld since {}",
+                &ticker, position.realized_gain, unrealized_gain, held_since)
+        }
+    }
+
+    /// Drains `quantity` shares worth of cost basis out of `lots` according to `mode`,
+    /// returning the total cost consumed. Errors (as a message) if the lots don't hold
+    /// enough quantity to cover the sell.
+    fn consume_lots(lots: &mut VecDeque<Lot>, quantity: i64, mode: CostBasis) -> Result<Decimal, String> {
+        let total_remaining: i64 = lots.iter().map(|lot| lot.quantity_remaining).sum();
+        if quantity > total_remaining {
+            return Err(String::from("Sell quantity exceeds remaining open lots"));
+        }
+
+        let blended_cost = match mode {
+            CostBasis::Average => {
+                let total_remaining: i64 = lots.iter().map(|lot| lot.quantity_remaining).sum();
+                let total_cost: Decimal = lots.iter()
+                    .map(|lot| lot.unit_cost * Decimal::from(lot.quantity_remaining))
+                    .sum();
+                if total_remaining > 0 {
+                    Some(total_cost / Decimal::from(total_remaining))
+                } else {
+                    None
+                }
+            },
+            CostBasis::Fifo | CostBasis::Lifo => None,
+        };
+
+        let mut remaining = quantity;
+        let mut consumed_cost = Decimal::ZERO;
+        while remaining > 0 {
+            let lot = match mode {
+                CostBasis::Fifo | CostBasis::Average => lots.front_mut(),
+                CostBasis::Lifo => lots.back_mut(),
+            };
+            let lot = match lot {
+                Some(lot) => lot,
+                None => return Err(String::from("Sell quantity exceeds remaining open lots")),
+            };
+
+            let take = remaining.min(lot.quantity_remaining);
+            let unit_cost = blended_cost.unwrap_or(lot.unit_cost);
+            consumed_cost += unit_cost * Decimal::from(take);
+            lot.quantity_remaining -= take;
+            remaining -= take;
+
+            if lot.quantity_remaining == 0 {
+                match mode {
+                    CostBasis::Fifo | CostBasis::Average => { lots.pop_front(); },
+                    CostBasis::Lifo => { lots.pop_back(); },
+                }
+            }
         }
+        Ok(consumed_cost)
     }
 
-    pub fn calculate_position(&self, ticker: &str, until: &Option<DateTime<Local>>) -> Position {
+    /// Replays every operation for `ticker` up to `until` to derive its current
+    /// position. Errors (rather than silently under-counting) if a sell can't be
+    /// matched against open lots, since that signals the history is inconsistent --
+    /// e.g. a backdated sell recorded after one that depended on those same lots.
+    pub fn calculate_position(&self, ticker: &str, until: &Option<DateTime<Local>>, cost_basis: CostBasis) -> Result<Position, String> {
         let now = &chrono::Local::now();
         let date = match until {
             Some(date) => date,
             None => now,
         };
 
-        let collection = self.db_client.database("stonks").collection("stocks");
+        let collection = self.db_client.database("stonks").collection(OPERATIONS_COLLECTION);
         let filter = doc!{
             "$and": [
                 { "ticker": &ticker },
@@ -229,40 +655,40 @@ impl App {
                 }
             ]
         };
-        let cursor = match collection.find(filter, None) {
+        let options = mongodb::options::FindOptions::builder()
+            .sort(doc! { "date": 1 })
+            .build();
+        let cursor = match collection.find(filter, options) {
             Ok(cursor) => cursor,
-            Err(e) => {
-                println!("{}", e);
-                return Position {
-                    ticker: ticker.to_string(),
-                    value: 0.0,
-                    quantity: 0,
-                    average_price: 0.0
-                }
-            }
+            Err(e) => return Err(e.to_string()),
         };
 
-        let mut total_amount = 0f64;
+        let mut lots: VecDeque<Lot> = VecDeque::new();
+        let mut total_amount = Decimal::ZERO;
         let mut total_quantity = 0i64;
+        let mut realized_gain = Decimal::ZERO;
 
         for document in cursor {
             if let Ok(document) = document {
                 let quantity = document.get_i64("quantity").unwrap();
                 let kind = OperationKind::from_str(document.get_str("kind").unwrap()).unwrap();
+                let price = Decimal::from_str(document.get_str("price").unwrap()).unwrap();
+                let op_date = document.get_datetime("date").unwrap().with_timezone(&Local);
                 match kind {
                     OperationKind::Buy => {
-                        let price = document.get_f64("price").unwrap();
-                        total_amount += price * quantity as f64;
+                        lots.push_back(Lot {
+                            date: op_date,
+                            quantity_remaining: quantity,
+                            unit_cost: price,
+                        });
+                        total_amount += price * Decimal::from(quantity);
                         total_quantity += quantity;
                     },
                     OperationKind::Sell => {
-                        /* When selling, we need to use the average price of the buys
-                         * at the moment for the average calculation to work. We may
-                         * take out too little if the current price is lower or too
-                         * much otherwise.
-                         */
-                        let price = total_amount / total_quantity as f64;
-                        total_amount -= price * quantity as f64;
+                        let consumed_cost = App::consume_lots(&mut lots, quantity, cost_basis)?;
+                        let proceeds = price * Decimal::from(quantity);
+                        realized_gain += proceeds - consumed_cost;
+                        total_amount -= consumed_cost;
                         total_quantity -= quantity;
                     }
                 }
@@ -270,23 +696,237 @@ impl App {
         }
 
         let average;
-        if total_quantity == 0 || total_amount == 0.0 {
-            average = 0.0;
+        if total_quantity == 0 || total_amount == Decimal::ZERO {
+            average = Decimal::ZERO;
         } else {
-            average = total_amount / total_quantity as f64;
+            average = total_amount / Decimal::from(total_quantity);
         }
+        let oldest_lot_date = lots.iter().map(|lot| lot.date).min();
 
-        Position {
+        Ok(Position {
             ticker: ticker.to_string(),
             value: total_amount,
             quantity: total_quantity,
             average_price: average,
+            realized_gain,
+            oldest_lot_date,
+        })
+    }
+
+    pub fn average_price(&self, ticker: &str, until: &Option<DateTime<Local>>, cost_basis: CostBasis) -> Result<Decimal, String> {
+        let position = self.calculate_position(ticker, until, cost_basis)?;
+        Ok(position.average_price)
+    }
+
+    pub fn cmd_value(&self, filter: &Option<String>) {
+        let source = match AlpacaPriceSource::new() {
+            Ok(source) => source,
+            Err(e) => { println!("{}", e); return }
+        };
+
+        let mut portfolio_value = Decimal::ZERO;
+        let mut portfolio_cost = Decimal::ZERO;
+        for ticker in self.matching_tickers(filter) {
+            let position = match self.calculate_position(&ticker, &None, CostBasis::Average) {
+                Ok(position) => position,
+                Err(e) => { println!("{}\t{}", &ticker, e); continue }
+            };
+            let price = match source.last_quote(&ticker) {
+                Ok(price) => price,
+                Err(e) => { println!("{}\t{}", &ticker, e); continue }
+            };
+            let market_value = price * Decimal::from(position.quantity);
+            let unrealized_gain = market_value - position.value;
+            portfolio_value += market_value;
+            portfolio_cost += position.value;
+            println!("{}\tvalue {:>9.2}\tcost {:>9.2}\tunrealized {:>9.2}",
+                &ticker, market_value, position.value, unrealized_gain);
+        }
+        println!("portfolio\tvalue {:>9.2}\tcost {:>9.2}\tunrealized {:>9.2}",
+            portfolio_value, portfolio_cost, portfolio_value - portfolio_cost);
+    }
+
+    pub fn cmd_export(&self, filter: &Option<String>, format: ExportFormat, cost_basis: CostBasis) {
+        for ticker in self.matching_tickers(filter) {
+            self.export_ticker(&ticker, format, cost_basis);
+        }
+    }
+
+    /// Replays every operation for `ticker` in date order, emitting one double-entry
+    /// transaction per operation. Sells are matched against open lots using `cost_basis`,
+    /// the same mode `sell`/`gains` use, so the realized gain booked to
+    /// `Income:CapitalGains` agrees with the rest of the tool for the same history.
+    fn export_ticker(&self, ticker: &str, format: ExportFormat, cost_basis: CostBasis) {
+        let collection = self.db_client.database("stonks").collection(OPERATIONS_COLLECTION);
+        let filter = doc! { "ticker": ticker };
+        let options = mongodb::options::FindOptions::builder()
+            .sort(doc! { "date": 1 })
+            .build();
+        let cursor = match collection.find(filter, options) {
+            Ok(cursor) => cursor,
+            Err(e) => { println!("{}", e); return }
+        };
+
+        let mut lots: VecDeque<Lot> = VecDeque::new();
+        for document in cursor {
+            let document = match document {
+                Ok(document) => document,
+                Err(_) => continue,
+            };
+            let quantity = document.get_i64("quantity").unwrap();
+            let kind = OperationKind::from_str(document.get_str("kind").unwrap()).unwrap();
+            let price = Decimal::from_str(document.get_str("price").unwrap()).unwrap();
+            let op_date = document.get_datetime("date").unwrap().with_timezone(&Local);
+            let amount = price * Decimal::from(quantity);
+
+            match kind {
+                OperationKind::Buy => {
+                    lots.push_back(Lot { date: op_date, quantity_remaining: quantity, unit_cost: price });
+                    let (stock_amount, cash_amount) = App::buy_posting_amounts(price, quantity);
+                    match format {
+                        ExportFormat::Ledger => {
+                            println!("{} Buy {}", op_date.format("%Y-%m-%d"), ticker);
+                            println!("    Assets:Stocks:{}          {} {} @ ${:.2} [{}]", ticker, quantity, ticker, price, op_date.format("%Y-%m-%d"));
+                            println!("    Assets:Cash                             ${:.2}\n", cash_amount);
+                            debug_assert_eq!(stock_amount + cash_amount, Decimal::ZERO);
+                        },
+                        ExportFormat::Csv => {
+                            println!("{},{},buy,{},{:.2},", op_date.format("%Y-%m-%d"), ticker, quantity, price);
+                        }
+                    }
+                },
+                OperationKind::Sell => {
+                    let consumed_cost = match App::consume_lots(&mut lots, quantity, cost_basis) {
+                        Ok(cost) => cost,
+                        Err(e) => { println!("{}", e); continue }
+                    };
+                    let (cash_amount, stock_amount, capital_gains_amount) =
+                        App::sell_posting_amounts(price, quantity, consumed_cost);
+                    let realized_gain = amount - consumed_cost;
+                    match format {
+                        ExportFormat::Ledger => {
+                            // The stock posting must be valued at the cost basis consumed,
+                            // not the sale price, or the postings won't sum to zero whenever
+                            // there's a realized gain or loss.
+                            let unit_cost_basis = if quantity != 0 {
+                                consumed_cost / Decimal::from(quantity)
+                            } else {
+                                Decimal::ZERO
+                            };
+                            println!("{} Sell {}", op_date.format("%Y-%m-%d"), ticker);
+                            println!("    Assets:Cash                             ${:.2}", cash_amount);
+                            println!("    Assets:Stocks:{}          -{} {} @ ${:.2}", ticker, quantity, ticker, unit_cost_basis);
+                            println!("    Income:CapitalGains                     ${:.2}\n", capital_gains_amount);
+                            debug_assert_eq!(cash_amount + stock_amount + capital_gains_amount, Decimal::ZERO);
+                        },
+                        ExportFormat::Csv => {
+                            println!("{},{},sell,{},{:.2},{:.2}",
+                                op_date.format("%Y-%m-%d"), ticker, quantity, price, realized_gain);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Dollar amounts of the Buy posting pair, `(stock, cash)`. Always sums to zero.
+    fn buy_posting_amounts(price: Decimal, quantity: i64) -> (Decimal, Decimal) {
+        let amount = price * Decimal::from(quantity);
+        (amount, -amount)
+    }
+
+    /// Dollar amounts of the Sell posting triple, `(cash, stock, capital_gains)`. The
+    /// stock posting is valued at the consumed cost basis rather than the sale price,
+    /// with the difference booked to capital gains, so the three always sum to zero.
+    fn sell_posting_amounts(price: Decimal, quantity: i64, consumed_cost: Decimal) -> (Decimal, Decimal, Decimal) {
+        let amount = price * Decimal::from(quantity);
+        let realized_gain = amount - consumed_cost;
+        (amount, -consumed_cost, -realized_gain)
+    }
+
+    pub fn cmd_candles(&self, ticker: &str, resolution: Resolution, from: &Option<DateTime<Local>>, to: &Option<DateTime<Local>>) {
+        let now = &chrono::Local::now();
+        let until = match to {
+            Some(date) => date,
+            None => now,
+        };
+
+        let mut and_clauses = vec![
+            doc! { "ticker": ticker },
+            doc! { "date": { "$lte": Bson::DateTime(until.with_timezone(&Utc)) } },
+        ];
+        if let Some(from_date) = from {
+            and_clauses.push(doc! { "date": { "$gte": Bson::DateTime(from_date.with_timezone(&Utc)) } });
+        }
+
+        let collection = self.db_client.database("stonks").collection(OPERATIONS_COLLECTION);
+        let options = mongodb::options::FindOptions::builder()
+            .sort(doc! { "date": 1 })
+            .build();
+        let cursor = match collection.find(doc! { "$and": and_clauses }, options) {
+            Ok(cursor) => cursor,
+            Err(e) => { println!("{}", e); return }
+        };
+
+        let bucket_seconds = resolution.seconds();
+        let operations: Vec<(i64, Decimal, i64)> = cursor.filter_map(|document| {
+            let document = document.ok()?;
+            let quantity = document.get_i64("quantity").ok()?;
+            let price = Decimal::from_str(document.get_str("price").ok()?).ok()?;
+            let op_date = document.get_datetime("date").ok()?;
+            Some((op_date.timestamp(), price, quantity))
+        }).collect();
+
+        let candles = App::bucket_candles(&operations, bucket_seconds);
+        if candles.is_empty() {
+            println!("No operations for {} in the given window", ticker);
+            return;
+        }
+
+        println!("bucket\t\t\topen\thigh\tlow\tclose\tvolume");
+        for (bucket_index, candle) in candles {
+            let bucket_start = Utc.timestamp_opt(bucket_index * bucket_seconds, 0).unwrap();
+            println!("{}\t{:.2}\t{:.2}\t{:.2}\t{:.2}\t{}",
+                bucket_start.format("%Y-%m-%d %H:%M:%S"),
+                candle.open, candle.high, candle.low, candle.close, candle.volume);
         }
     }
 
-    pub fn average_price(&self, ticker: &str, until: &Option<DateTime<Local>>) -> f64 {
-        let position = self.calculate_position(ticker, until);
-        return position.average_price;
+    /// Aggregates `(unix_timestamp, price, signed_quantity)` operations into OHLC+volume
+    /// candles of width `bucket_seconds`, forward-filling any gap bucket from the previous
+    /// close so the returned series is contiguous from the first to the last bucket.
+    fn bucket_candles(operations: &[(i64, Decimal, i64)], bucket_seconds: i64) -> Vec<(i64, Candle)> {
+        let mut buckets: BTreeMap<i64, Candle> = BTreeMap::new();
+        for (timestamp, price, quantity) in operations {
+            let quantity = quantity.abs();
+            let bucket_index = timestamp.div_euclid(bucket_seconds);
+            buckets.entry(bucket_index)
+                .and_modify(|candle| {
+                    candle.high = candle.high.max(*price);
+                    candle.low = candle.low.min(*price);
+                    candle.close = *price;
+                    candle.volume += quantity;
+                })
+                .or_insert(Candle { open: *price, high: *price, low: *price, close: *price, volume: quantity });
+        }
+
+        if buckets.is_empty() {
+            return Vec::new();
+        }
+
+        let first_bucket = *buckets.keys().next().unwrap();
+        let last_bucket = *buckets.keys().last().unwrap();
+        let mut previous_close = None;
+        let mut candles = Vec::new();
+        for bucket_index in first_bucket..=last_bucket {
+            let candle = *buckets.entry(bucket_index).or_insert_with(|| {
+                let close = previous_close.unwrap_or(Decimal::ZERO);
+                Candle { open: close, high: close, low: close, close, volume: 0 }
+            });
+            previous_close = Some(candle.close);
+            candles.push((bucket_index, candle));
+        }
+        candles
     }
 
     pub fn process_statement(&self, statement: Statement) {
@@ -296,11 +936,23 @@ impl App {
             Command::Buy { ticker, quantity, price, date } => {
                 self.cmd_buy(ticker, quantity, price, date);
             },
-            Command::Sell { ticker, quantity, price, date } => {
-                self.cmd_sell(ticker, quantity, price, date);
+            Command::Sell { ticker, quantity, price, date, cost_basis } => {
+                self.cmd_sell(ticker, quantity, price, date, *cost_basis);
+            },
+            Command::AvgPrice { filter, until, cost_basis } => {
+                self.cmd_avgprice(filter, until, *cost_basis);
+            },
+            Command::Gains { filter, until, cost_basis } => {
+                self.cmd_gains(filter, until, *cost_basis);
+            },
+            Command::Value { filter } => {
+                self.cmd_value(filter);
+            },
+            Command::Export { filter, format, cost_basis } => {
+                self.cmd_export(filter, *format, *cost_basis);
             },
-            Command::AvgPrice { filter, until } => {
-                self.cmd_avgprice(filter, until);
+            Command::Candles { ticker, resolution, from, to } => {
+                self.cmd_candles(ticker, *resolution, from, to);
             }
         }
     }
@@ -346,3 +998,131 @@ fn main() {
     let mut app = App::new();
     app.run()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lot(quantity: i64, unit_cost: &str) -> Lot {
+        Lot {
+            date: Local.timestamp(0, 0),
+            quantity_remaining: quantity,
+            unit_cost: Decimal::from_str(unit_cost).unwrap(),
+        }
+    }
+
+    #[test]
+    fn fifo_consumes_oldest_lot_first() {
+        let mut lots = VecDeque::from(vec![lot(10, "100"), lot(10, "200")]);
+        let consumed_cost = App::consume_lots(&mut lots, 5, CostBasis::Fifo).unwrap();
+        assert_eq!(consumed_cost, Decimal::from_str("500").unwrap());
+        assert_eq!(lots.len(), 2);
+        assert_eq!(lots[0].quantity_remaining, 5);
+        assert_eq!(lots[1].quantity_remaining, 10);
+    }
+
+    #[test]
+    fn fifo_partial_fill_spans_two_lots() {
+        let mut lots = VecDeque::from(vec![lot(5, "100"), lot(10, "200")]);
+        let consumed_cost = App::consume_lots(&mut lots, 8, CostBasis::Fifo).unwrap();
+        // 5 @ 100 + 3 @ 200
+        assert_eq!(consumed_cost, Decimal::from_str("1100").unwrap());
+        assert_eq!(lots.len(), 1);
+        assert_eq!(lots[0].quantity_remaining, 7);
+    }
+
+    #[test]
+    fn lifo_consumes_newest_lot_first() {
+        let mut lots = VecDeque::from(vec![lot(10, "100"), lot(10, "200")]);
+        let consumed_cost = App::consume_lots(&mut lots, 5, CostBasis::Lifo).unwrap();
+        assert_eq!(consumed_cost, Decimal::from_str("1000").unwrap());
+        assert_eq!(lots.len(), 2);
+        assert_eq!(lots[0].quantity_remaining, 10);
+        assert_eq!(lots[1].quantity_remaining, 5);
+    }
+
+    #[test]
+    fn average_blends_cost_across_all_remaining_lots() {
+        let mut lots = VecDeque::from(vec![lot(10, "100"), lot(10, "200")]);
+        // Blended unit cost is (10*100 + 10*200) / 20 = 150
+        let consumed_cost = App::consume_lots(&mut lots, 15, CostBasis::Average).unwrap();
+        assert_eq!(consumed_cost, Decimal::from_str("2250").unwrap());
+        let remaining: i64 = lots.iter().map(|lot| lot.quantity_remaining).sum();
+        assert_eq!(remaining, 5);
+    }
+
+    #[test]
+    fn oversell_is_rejected_without_mutating_lots() {
+        let mut lots = VecDeque::from(vec![lot(10, "100")]);
+        let result = App::consume_lots(&mut lots, 11, CostBasis::Fifo);
+        assert!(result.is_err());
+        assert_eq!(lots[0].quantity_remaining, 10);
+    }
+
+    fn op(timestamp: i64, price: &str, quantity: i64) -> (i64, Decimal, i64) {
+        (timestamp, Decimal::from_str(price).unwrap(), quantity)
+    }
+
+    #[test]
+    fn candle_bucket_tracks_open_high_low_close_volume() {
+        let ops = vec![op(0, "100", 10), op(10, "120", -5), op(20, "90", 3)];
+        let candles = App::bucket_candles(&ops, 60);
+        assert_eq!(candles.len(), 1);
+        let (bucket_index, candle) = candles[0];
+        assert_eq!(bucket_index, 0);
+        assert_eq!(candle.open, Decimal::from_str("100").unwrap());
+        assert_eq!(candle.high, Decimal::from_str("120").unwrap());
+        assert_eq!(candle.low, Decimal::from_str("90").unwrap());
+        assert_eq!(candle.close, Decimal::from_str("90").unwrap());
+        // Volume sums the absolute quantity of each operation, buy or sell.
+        assert_eq!(candle.volume, 18);
+    }
+
+    #[test]
+    fn candle_gaps_are_forward_filled_from_previous_close() {
+        // Two operations 3 buckets apart (bucket width 60s), nothing in between.
+        let ops = vec![op(0, "100", 1), op(180, "150", 1)];
+        let candles = App::bucket_candles(&ops, 60);
+        assert_eq!(candles.len(), 4);
+        let (_, filled) = candles[1];
+        assert_eq!(filled.open, Decimal::from_str("100").unwrap());
+        assert_eq!(filled.close, Decimal::from_str("100").unwrap());
+        assert_eq!(filled.volume, 0);
+        let (_, last) = candles[3];
+        assert_eq!(last.close, Decimal::from_str("150").unwrap());
+    }
+
+    #[test]
+    fn candle_bucketing_with_no_operations_is_empty() {
+        let candles = App::bucket_candles(&[], 60);
+        assert!(candles.is_empty());
+    }
+
+    #[test]
+    fn buy_postings_balance_to_zero() {
+        let (stock, cash) = App::buy_posting_amounts(Decimal::from_str("100").unwrap(), 10);
+        assert_eq!(stock, Decimal::from_str("1000").unwrap());
+        assert_eq!(cash, Decimal::from_str("-1000").unwrap());
+        assert_eq!(stock + cash, Decimal::ZERO);
+    }
+
+    #[test]
+    fn sell_postings_balance_to_zero_on_a_gain() {
+        // Sold 10 @ 150 against a consumed cost basis of 100/share: a 500 gain.
+        let (cash, stock, capital_gains) =
+            App::sell_posting_amounts(Decimal::from_str("150").unwrap(), 10, Decimal::from_str("1000").unwrap());
+        assert_eq!(cash, Decimal::from_str("1500").unwrap());
+        assert_eq!(stock, Decimal::from_str("-1000").unwrap());
+        assert_eq!(capital_gains, Decimal::from_str("-500").unwrap());
+        assert_eq!(cash + stock + capital_gains, Decimal::ZERO);
+    }
+
+    #[test]
+    fn sell_postings_balance_to_zero_on_a_loss() {
+        // Sold 10 @ 80 against a consumed cost basis of 100/share: a 200 loss.
+        let (cash, stock, capital_gains) =
+            App::sell_posting_amounts(Decimal::from_str("80").unwrap(), 10, Decimal::from_str("1000").unwrap());
+        assert_eq!(capital_gains, Decimal::from_str("200").unwrap());
+        assert_eq!(cash + stock + capital_gains, Decimal::ZERO);
+    }
+}